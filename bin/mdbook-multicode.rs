@@ -4,16 +4,59 @@ use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
 use mdbook_multicode::Multicode;
 use semver::{Version, VersionReq};
 use std::io;
+use std::path::Path;
 use std::process;
 
 pub fn make_app() -> Command {
-    Command::new("nop-preprocessor")
-        .about("A mdbook preprocessor which does precisely nothing")
+    Command::new("mdbook-multicode")
+        .about("A mdbook preprocessor for multilanguage code examples")
         .subcommand(
             Command::new("supports")
                 .arg(Arg::new("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            Command::new("install")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book, where book.toml is"),
+                )
+                .about("Set up a book to use this preprocessor"),
+        )
+        .subcommand(
+            Command::new("check")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book, where book.toml is"),
+                )
+                .about("Report problems in the multicode blocks of a book, without building it"),
+        )
+        .subcommand(
+            Command::new("extract")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book, where book.toml is"),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .required(true)
+                        .help("Directory to write the code and its manifest into"),
+                )
+                .about("Write the code of every language section to files"),
+        )
+        .subcommand(
+            Command::new("test")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book, where book.toml is"),
+                )
+                .about("Run the code of every language section through its configured command"),
+        )
 }
 
 fn main() {
@@ -24,8 +67,28 @@ fn main() {
 
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args);
+    } else if let Some(sub_args) = matches.subcommand_matches("install") {
+        if let Err(e) = handle_install(sub_args) {
+            eprintln!("{:#}", e);
+            process::exit(1);
+        }
+    } else if let Some(sub_args) = matches.subcommand_matches("check") {
+        if let Err(e) = handle_check(sub_args) {
+            eprintln!("{:#}", e);
+            process::exit(1);
+        }
+    } else if let Some(sub_args) = matches.subcommand_matches("extract") {
+        if let Err(e) = handle_extract(sub_args) {
+            eprintln!("{:#}", e);
+            process::exit(1);
+        }
+    } else if let Some(sub_args) = matches.subcommand_matches("test") {
+        if let Err(e) = handle_test(sub_args) {
+            eprintln!("{:#}", e);
+            process::exit(1);
+        }
     } else if let Err(e) = handle_preprocessing(&preprocessor) {
-        eprintln!("{}", e);
+        eprintln!("{:#}", e);
         process::exit(1);
     }
 }
@@ -52,6 +115,58 @@ fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
     Ok(())
 }
 
+fn handle_install(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let changes = mdbook_multicode::install(Path::new(dir))?;
+
+    if changes.is_empty() {
+        println!("Already installed, nothing to do");
+    }
+    for change in changes {
+        println!("{}", change);
+    }
+
+    Ok(())
+}
+
+fn handle_check(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let blocks = mdbook_multicode::check(Path::new(dir))?;
+
+    println!("Checked {} multicode block(s), no problems found", blocks);
+
+    Ok(())
+}
+
+fn handle_extract(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let out = sub_args
+        .get_one::<String>("out")
+        .expect("Required argument");
+    let files = mdbook_multicode::extract(Path::new(dir), Path::new(out))?;
+
+    println!(
+        "Wrote {} file(s) and {} to {}",
+        files.len(),
+        mdbook_multicode::MANIFEST_FILE_NAME,
+        out
+    );
+
+    Ok(())
+}
+
+fn handle_test(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let summary = mdbook_multicode::test(Path::new(dir))?;
+
+    println!(
+        "\ntest result: ok. {} passed; {} ignored",
+        summary.passed, summary.skipped
+    );
+
+    Ok(())
+}
+
 fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
     let renderer = sub_args
         .get_one::<String>("renderer")
@@ -0,0 +1,106 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use mdbook::Config;
+
+/// Script behind the language pickers.
+pub const MULTICODE_JS: &str = include_str!("assets/multicode.js");
+/// Styles of the language pickers, using the mdbook theme's colours.
+pub const MULTICODE_CSS: &str = include_str!("assets/multicode.css");
+
+pub const JS_FILE_NAME: &str = "multicode.js";
+pub const CSS_FILE_NAME: &str = "multicode.css";
+
+/// Writes `multicode.js` and `multicode.css` into `dir`, creating it if
+/// needed. Files that are already up to date are left alone; the ones written
+/// are returned.
+pub fn install_assets(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+
+    let mut written = Vec::new();
+    for (name, content) in [(JS_FILE_NAME, MULTICODE_JS), (CSS_FILE_NAME, MULTICODE_CSS)] {
+        let path = dir.join(name);
+        if write_if_changed(&path, content)? {
+            written.push(path);
+        }
+    }
+    Ok(written)
+}
+
+/// The assets listed in `output.html.additional-js` and `additional-css`, as
+/// paths relative to the book root.
+#[derive(Debug, Default)]
+pub(crate) struct Registered {
+    pub(crate) js: Option<PathBuf>,
+    pub(crate) css: Option<PathBuf>,
+}
+
+impl Registered {
+    pub(crate) fn from_config(config: &Config) -> Registered {
+        let find = |key: &str, name: &str| {
+            config
+                .get(key)
+                .and_then(|value| value.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.as_str())
+                .map(PathBuf::from)
+                .find(|path| path.file_name().is_some_and(|file| file == name))
+        };
+
+        Registered {
+            js: find("output.html.additional-js", JS_FILE_NAME),
+            css: find("output.html.additional-css", CSS_FILE_NAME),
+        }
+    }
+
+    /// Warns about registered files under `root` that are missing or differ
+    /// from this version of the preprocessor. They are left alone, as they
+    /// may have been customised on purpose.
+    pub(crate) fn check(&self, root: &Path) {
+        for (path, content) in [(&self.js, MULTICODE_JS), (&self.css, MULTICODE_CSS)] {
+            let Some(path) = path else {
+                continue;
+            };
+            match fs::read_to_string(root.join(path)) {
+                Ok(existing) if existing == content => {}
+                Ok(_) => eprintln!(
+                    "Warning: {} differs from the one bundled with this version, \
+                     run `mdbook-multicode install` to update it",
+                    path.display()
+                ),
+                Err(_) => eprintln!(
+                    "Warning: {} is missing, run `mdbook-multicode install` to write it",
+                    path.display()
+                ),
+            }
+        }
+    }
+
+    /// The `<script>` and `<style>` tags a chapter needs for whatever is not
+    /// registered, empty when both are.
+    pub(crate) fn inline_fallback(&self) -> String {
+        let mut inline = String::new();
+        if self.js.is_none() {
+            inline.push_str("<script>\n");
+            inline.push_str(MULTICODE_JS);
+            inline.push_str("</script>\n\n");
+        }
+        if self.css.is_none() {
+            inline.push_str("<style>\n");
+            inline.push_str(MULTICODE_CSS);
+            inline.push_str("</style>\n\n");
+        }
+        inline
+    }
+}
+
+/// Returns whether the file had to be written.
+fn write_if_changed(path: &Path, content: &str) -> io::Result<bool> {
+    if fs::read_to_string(path).is_ok_and(|existing| existing == content) {
+        return Ok(false);
+    }
+    fs::write(path, content)?;
+    Ok(true)
+}
@@ -0,0 +1,61 @@
+const MULTICODE_LANGUAGE_KEY = "mdbook-multicode-language";
+
+const showCodeExample = (tabClass, to) => {
+    const tabs = document.getElementsByClassName(tabClass);
+    for (let i = 0; i < tabs.length; i++) {
+        tabs[i].style.display = "none";
+    }
+
+    const theTab = document.getElementById(to);
+    theTab.style.display = "block";
+
+    // Keep the picker in step, when the change did not come from it
+    const select = document.querySelector(`select.code-example[data-group="${tabClass}"]`);
+    if (select) {
+        select.value = to;
+    }
+    const buttons = document.querySelectorAll(`button.code-example-tab[data-group="${tabClass}"]`);
+    for (const button of buttons) {
+        const active = button.dataset.target === to;
+        button.classList.toggle("active", active);
+        button.setAttribute("aria-selected", active);
+    }
+}
+
+// With `sticky`, every block on the page follows the pick, and it is
+// remembered for the next pages
+const changeCodeExample = (tabClass, to, sticky) => {
+    // console.debug(to);
+    showCodeExample(tabClass, to);
+    if (!sticky) {
+        return;
+    }
+
+    const lang = document.getElementById(to).dataset.lang;
+    try {
+        localStorage.setItem(MULTICODE_LANGUAGE_KEY, lang);
+    } catch (e) {
+        // Storage may be disabled, the page still syncs
+    }
+
+    const synced = new Set([tabClass]);
+    for (const pane of document.querySelectorAll(`div[data-lang="${CSS.escape(lang)}"]`)) {
+        if (!synced.has(pane.className)) {
+            synced.add(pane.className);
+            showCodeExample(pane.className, pane.id);
+        }
+    }
+}
+
+// Shows the remembered language if the block has it, `fallback` otherwise
+const restoreCodeExample = (tabClass, fallback) => {
+    let lang = null;
+    try {
+        lang = localStorage.getItem(MULTICODE_LANGUAGE_KEY);
+    } catch (e) {
+        // Storage may be disabled, use the default
+    }
+
+    const pane = lang && document.querySelector(`div.${tabClass}[data-lang="${CSS.escape(lang)}"]`);
+    showCodeExample(tabClass, pane ? pane.id : fallback);
+}
@@ -0,0 +1,37 @@
+use std::path::Path;
+
+use anyhow::Context;
+use mdbook::errors::Error;
+use mdbook::{BookItem, MDBook};
+
+use crate::config::MulticodeConfig;
+use crate::diagnostics::Diagnostics;
+use crate::parser::parse_with_config;
+
+/// Parses every multicode block of the book at `root` as a build would,
+/// without rendering anything, and fails listing every problem found. Warnings
+/// count as problems too. Returns the number of blocks checked.
+pub fn check(root: &Path) -> Result<usize, Error> {
+    let book = MDBook::load(root)
+        .with_context(|| format!("Couldn't load the book at {}", root.display()))?;
+    let config = MulticodeConfig::from_book(&book.config)?;
+    let mut diagnostics = Diagnostics::new(true);
+    let src_dir = book.root.join(&book.config.book.src);
+
+    let mut blocks = 0;
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            blocks += parse_with_config(
+                &chapter.content,
+                &config,
+                chapter.source_path.as_deref(),
+                Some(&src_dir),
+                &mut diagnostics,
+            )
+            .len();
+        }
+    }
+
+    diagnostics.finish()?;
+    Ok(blocks)
+}
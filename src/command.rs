@@ -0,0 +1,44 @@
+use std::fs;
+use std::process::Command;
+
+use anyhow::Context;
+use mdbook::errors::Error;
+
+/// Runs `template` on `code`, written to `main.<ext>` in a temporary directory
+/// the command runs in, and returns what it printed to stdout.
+///
+/// `{file}` in the template stands for the file's path. The command is split
+/// into words like a shell would, but run directly rather than through one. It
+/// fails with the command's output unless it exits successfully.
+pub(crate) fn run_code(template: &str, code: &str, ext: &str) -> Result<String, Error> {
+    let dir = tempfile::tempdir().context("Couldn't create a temporary directory")?;
+    let file = dir.path().join(format!("main.{ext}"));
+    fs::write(&file, code).with_context(|| format!("Couldn't write {}", file.display()))?;
+
+    let file = file.to_string_lossy();
+    let words: Vec<String> = shlex::split(template)
+        .context("Invalid command")?
+        .iter()
+        .map(|word| word.replace("{file}", &file))
+        .collect();
+    let (program, args) = words.split_first().context("Empty command")?;
+
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(dir.path())
+        .output()
+        .with_context(|| format!("Couldn't run `{program}`"))?;
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+
+    let mut message = format!("`{template}` failed with {}", output.status);
+    for stream in [&output.stdout, &output.stderr] {
+        let text = String::from_utf8_lossy(stream);
+        if !text.trim().is_empty() {
+            message.push('\n');
+            message.push_str(text.trim_end());
+        }
+    }
+    anyhow::bail!(message)
+}
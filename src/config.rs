@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use mdbook::errors::Error;
+use serde::Deserialize;
+
+/// Name of the preprocessor, and of its table in `book.toml`.
+pub(crate) const NAME: &str = "multicode";
+/// What the table used to be called.
+pub(crate) const DEPRECATED_NAME: &str = "http-api";
+
+/// Keys that mdbook itself reads from every `[preprocessor.*]` table. They
+/// are not ours to validate, so they are stripped before deserializing.
+const MDBOOK_KEYS: &[&str] = &["command", "renderers", "before", "after", "optional"];
+
+/// Common spellings of a language, and the id they stand for. The
+/// `aliases` table of the configuration is looked up first.
+const BUILTIN_ALIASES: &[(&str, &str)] = &[
+    ("c++", "cpp"),
+    ("cc", "cpp"),
+    ("cxx", "cpp"),
+    ("c#", "csharp"),
+    ("cs", "csharp"),
+    ("golang", "go"),
+    ("js", "javascript"),
+    ("kt", "kotlin"),
+    ("objc", "objectivec"),
+    ("objective-c", "objectivec"),
+    ("py", "python"),
+    ("python3", "python"),
+    ("rb", "ruby"),
+    ("rs", "rust"),
+    ("sh", "bash"),
+    ("shell", "bash"),
+    ("ts", "typescript"),
+    ("yml", "yaml"),
+];
+
+/// Book-wide settings, read from the `[preprocessor.multicode]` table of
+/// `book.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MulticodeConfig {
+    /// Info string that marks a fenced code block as a multicode block.
+    pub fence: String,
+    /// Marker that opens a language section, followed by the language name.
+    pub section_start: String,
+    /// Marker that closes a language section.
+    pub section_end: String,
+    /// Language shown first when a block has it, instead of the first one
+    /// written.
+    pub default_language: Option<String>,
+    /// Order of the languages in every block. Languages not listed come after,
+    /// as written, and the first one is shown unless `default-language` is in
+    /// the block.
+    pub languages: Vec<String>,
+    /// Names shown in the picker for language ids, when a section does not
+    /// give its own.
+    pub labels: HashMap<String, String>,
+    /// Other spellings of language ids, on top of the built-in ones, so
+    /// `c++` and `cxx` sections are both highlighted and synced as `cpp`.
+    pub aliases: HashMap<String, String>,
+    /// File extensions for extracted code, on top of the built-in ones.
+    pub extensions: HashMap<String, String>,
+    /// Commands `mdbook-multicode test` runs the code of each language with.
+    /// `{file}` stands for the file holding the code.
+    pub test_commands: HashMap<String, String>,
+    /// Commands that only check the code of `no_run` sections, per language.
+    pub check_commands: HashMap<String, String>,
+    /// Where the output of sections marked `run` is kept between builds,
+    /// relative to the book root.
+    pub output_cache: PathBuf,
+    /// How the language picker is presented.
+    pub ui: Ui,
+    /// Picking a language switches every block on the page to it, and is
+    /// remembered for the next pages.
+    pub sticky: bool,
+    /// How the code of each language is emitted.
+    pub code_blocks: CodeBlocks,
+    /// Fail the build on malformed blocks instead of warning about them.
+    pub strict: bool,
+    /// What to do when a block has the same language more than once.
+    pub duplicate_languages: DuplicatePolicy,
+    /// Languages every block is expected to have.
+    pub required_languages: Vec<String>,
+    /// How blocks lacking one of `required-languages` are reported.
+    pub missing_languages: MissingLanguages,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ui {
+    /// A `<select>` dropdown above the code.
+    Select,
+    /// A row of tab buttons above the code.
+    Tabs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CodeBlocks {
+    /// Ready-made `<pre><code>` HTML.
+    Html,
+    /// Fenced Markdown code blocks, which mdbook renders like any other: with
+    /// playground buttons, hidden lines and the copy button.
+    Native,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    /// Fail the build.
+    Error,
+    /// Append the later sections to the first one.
+    Merge,
+    /// Keep the first section, drop the others.
+    KeepFirst,
+    /// Keep the last section's code, in the first one's place.
+    KeepLast,
+    /// Keep them all, numbering their labels.
+    Allow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingLanguages {
+    /// Print a warning, and build anyway unless `strict` is set.
+    Warn,
+    /// Fail the build.
+    Error,
+}
+
+impl Default for MulticodeConfig {
+    fn default() -> Self {
+        MulticodeConfig {
+            fence: "multicode".to_owned(),
+            section_start: ">>>>>".to_owned(),
+            section_end: "<<<<<".to_owned(),
+            default_language: None,
+            languages: Vec::new(),
+            labels: HashMap::new(),
+            aliases: HashMap::new(),
+            extensions: HashMap::new(),
+            test_commands: HashMap::new(),
+            check_commands: HashMap::new(),
+            output_cache: PathBuf::from(".multicode-cache"),
+            ui: Ui::Select,
+            sticky: false,
+            code_blocks: CodeBlocks::Html,
+            strict: false,
+            duplicate_languages: DuplicatePolicy::Error,
+            required_languages: Vec::new(),
+            missing_languages: MissingLanguages::Warn,
+        }
+    }
+}
+
+impl MulticodeConfig {
+    /// Reads the `[preprocessor.multicode]` table of a book's configuration,
+    /// or the deprecated `[preprocessor.http-api]` one.
+    pub fn from_book(config: &mdbook::Config) -> Result<MulticodeConfig, Error> {
+        let table = config.get_preprocessor(NAME).or_else(|| {
+            let table = config.get_preprocessor(DEPRECATED_NAME)?;
+            eprintln!(
+                "Warning: [preprocessor.{DEPRECATED_NAME}] is deprecated, \
+                 rename it to [preprocessor.{NAME}]"
+            );
+            Some(table)
+        });
+        MulticodeConfig::from_table(table)
+    }
+
+    /// Parses and validates the preprocessor table. A missing table yields the
+    /// defaults.
+    pub fn from_table(table: Option<&toml::value::Table>) -> Result<MulticodeConfig, Error> {
+        let mut table = table.cloned().unwrap_or_default();
+        for key in MDBOOK_KEYS {
+            table.remove(*key);
+        }
+
+        let config: MulticodeConfig = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| anyhow::anyhow!("Invalid multicode configuration: {e}"))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.fence.is_empty() || self.fence.contains(char::is_whitespace) {
+            anyhow::bail!(
+                "Invalid multicode configuration: `fence` must be a single word, got {:?}",
+                self.fence
+            );
+        }
+        for (key, marker) in [
+            ("section-start", &self.section_start),
+            ("section-end", &self.section_end),
+        ] {
+            if marker.trim().is_empty() || marker.trim() != marker {
+                anyhow::bail!(
+                    "Invalid multicode configuration: `{key}` must be non-empty and have no \
+                     surrounding whitespace, got {marker:?}"
+                );
+            }
+        }
+        if self.section_start == self.section_end {
+            anyhow::bail!(
+                "Invalid multicode configuration: `section-start` and `section-end` must differ"
+            );
+        }
+        if let Some(lang) = &self.default_language {
+            if !is_language_name(lang) {
+                anyhow::bail!(
+                    "Invalid multicode configuration: `default-language` must be a language \
+                     name, got {lang:?}"
+                );
+            }
+        }
+        for (key, langs) in [
+            ("languages", &self.languages),
+            ("required-languages", &self.required_languages),
+        ] {
+            if let Some(lang) = langs.iter().find(|lang| !is_language_name(lang)) {
+                anyhow::bail!(
+                    "Invalid multicode configuration: `{key}` must list language names, got \
+                     {lang:?}"
+                );
+            }
+        }
+        for (alias, lang) in &self.aliases {
+            if !is_language_name(alias) || !is_language_name(lang) {
+                anyhow::bail!(
+                    "Invalid multicode configuration: `aliases` must map language names to \
+                     language names, got {alias:?} = {lang:?}"
+                );
+            }
+        }
+        for (lang, ext) in &self.extensions {
+            let valid = !ext.is_empty()
+                && ext
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+            if !is_language_name(lang) || !valid || ext.starts_with('.') {
+                anyhow::bail!(
+                    "Invalid multicode configuration: `extensions` must map language names to \
+                     file extensions without the leading dot, got {lang:?} = {ext:?}"
+                );
+            }
+        }
+        for (key, commands) in [
+            ("test-commands", &self.test_commands),
+            ("check-commands", &self.check_commands),
+        ] {
+            for (lang, command) in commands {
+                let valid = shlex::split(command).is_some_and(|words| !words.is_empty());
+                if !is_language_name(lang) || !valid {
+                    anyhow::bail!(
+                        "Invalid multicode configuration: `{key}` must map language names to \
+                         commands, got {lang:?} = {command:?}"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The id a language is known by: what its spelling is an alias of, in
+    /// lowercase.
+    pub fn canonical_language(&self, lang: &str) -> String {
+        let lang = lang.to_lowercase();
+        let alias = self
+            .aliases
+            .iter()
+            .find(|(alias, _)| alias.to_lowercase() == lang)
+            .map(|(_, canonical)| canonical.as_str())
+            .or_else(|| {
+                BUILTIN_ALIASES
+                    .iter()
+                    .find(|(alias, _)| *alias == lang)
+                    .map(|(_, canonical)| *canonical)
+            });
+        match alias {
+            Some(canonical) => canonical.to_lowercase(),
+            None => lang,
+        }
+    }
+
+    /// Extension of the files `lang` code is extracted to, without the dot.
+    pub fn extension(&self, lang: &str) -> String {
+        self.extensions
+            .iter()
+            .find(|(key, _)| self.canonical_language(key) == lang)
+            .map(|(_, ext)| ext.as_str())
+            .or_else(|| {
+                BUILTIN_EXTENSIONS
+                    .iter()
+                    .find(|(key, _)| *key == lang)
+                    .map(|(_, ext)| *ext)
+            })
+            .unwrap_or(lang)
+            .to_owned()
+    }
+
+    /// The language of files with extension `ext`: the one it is set for in
+    /// `extensions`, or a built-in one, or what it is an alias of. `None` when
+    /// it names no language.
+    pub fn language_for_extension(&self, ext: &str) -> Option<String> {
+        let lang = self
+            .extensions
+            .iter()
+            .find(|(_, value)| value.eq_ignore_ascii_case(ext))
+            .map(|(lang, _)| lang.as_str())
+            .or_else(|| {
+                BUILTIN_EXTENSIONS
+                    .iter()
+                    .find(|(_, value)| value.eq_ignore_ascii_case(ext))
+                    .map(|(lang, _)| *lang)
+            });
+        match lang {
+            Some(lang) => Some(self.canonical_language(lang)),
+            None => {
+                let is_alias = self
+                    .aliases
+                    .keys()
+                    .any(|alias| alias.eq_ignore_ascii_case(ext))
+                    || BUILTIN_ALIASES
+                        .iter()
+                        .any(|(alias, _)| alias.eq_ignore_ascii_case(ext));
+                is_alias.then(|| self.canonical_language(ext))
+            }
+        }
+    }
+
+    /// The command running `lang` code, or only checking it with `check`, if
+    /// there is one.
+    pub(crate) fn test_command(&self, lang: &str, check: bool) -> Option<&str> {
+        let commands = if check {
+            &self.check_commands
+        } else {
+            &self.test_commands
+        };
+        commands
+            .iter()
+            .find(|(key, _)| self.canonical_language(key) == lang)
+            .map(|(_, command)| command.as_str())
+    }
+
+    /// The picker label set for `lang` in the `labels` table, if any.
+    pub(crate) fn label_for(&self, lang: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(key, _)| self.canonical_language(key) == lang)
+            .map(|(_, label)| label.as_str())
+    }
+}
+
+/// File extensions of languages, for `mdbook-multicode extract`. Languages
+/// missing here and from the `extensions` table use their id.
+const BUILTIN_EXTENSIONS: &[(&str, &str)] = &[
+    ("bash", "sh"),
+    ("c", "c"),
+    ("cpp", "cpp"),
+    ("csharp", "cs"),
+    ("go", "go"),
+    ("haskell", "hs"),
+    ("java", "java"),
+    ("javascript", "js"),
+    ("kotlin", "kt"),
+    ("markdown", "md"),
+    ("objectivec", "m"),
+    ("python", "py"),
+    ("ruby", "rb"),
+    ("rust", "rs"),
+    ("swift", "swift"),
+    ("typescript", "ts"),
+];
+
+/// Whether `name` can be a language id: letters and digits, with `+`, `#`,
+/// `.`, `_` and `-` after the first one.
+pub(crate) fn is_language_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '#' | '.' | '_' | '-'))
+}
@@ -0,0 +1,57 @@
+/// The blockquotes and list items a multicode block is nested in, as seen on
+/// the line of its opening fence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Container {
+    /// What continuation lines start with: the blockquote markers, with
+    /// everything else turned into indentation.
+    prefix: String,
+    /// What came before the fence on its own line, when it held a list marker
+    /// that has to be kept on the first line of the output.
+    marker: Option<String>,
+}
+
+impl Container {
+    /// `prefix` is the text between the start of the opening fence's line and
+    /// the fence itself.
+    pub(crate) fn from_prefix(prefix: &str) -> Container {
+        let has_marker = prefix.chars().any(|c| !matches!(c, '>' | ' ' | '\t'));
+
+        Container {
+            prefix: prefix
+                .chars()
+                .map(|c| if matches!(c, '>' | '\t') { c } else { ' ' })
+                .collect(),
+            marker: has_marker.then(|| prefix.to_owned()),
+        }
+    }
+
+    /// Puts `text` where the opening fence line was, inside this container.
+    ///
+    /// A list marker stays on the first line of `text`: on a line of its own
+    /// it would make an empty item, which can't interrupt a paragraph, and a
+    /// bare `-` under one is read as a heading underline.
+    pub(crate) fn wrap(&self, text: &str) -> String {
+        match &self.marker {
+            Some(marker) => {
+                let (first, rest) = text.split_at(text.find('\n').map_or(text.len(), |i| i + 1));
+                format!("{marker}{first}{}", self.indent(rest))
+            }
+            None => format!("{}\n{}", self.prefix.trim_end(), self.indent(text)),
+        }
+    }
+
+    /// Prefixes every line of `text` so it stays inside this container.
+    fn indent(&self, text: &str) -> String {
+        let mut indented = String::with_capacity(text.len());
+        for line in text.split_inclusive('\n') {
+            if line.trim().is_empty() {
+                indented.push_str(self.prefix.trim_end());
+                indented.push_str(line.trim_start_matches([' ', '\t']));
+            } else {
+                indented.push_str(&self.prefix);
+                indented.push_str(line);
+            }
+        }
+        indented
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use mdbook::errors::Error;
+
+/// Something wrong with a multicode block, pinned to where it was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Chapter source file, relative to the book's `src` directory.
+    pub source_path: Option<PathBuf>,
+    /// 1-based line number within the chapter.
+    pub line: usize,
+    /// 1-based column number within the line.
+    pub column: usize,
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Reported, but the build carries on unless `strict` is set.
+    Warning,
+    /// Always fails the build.
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// A language section was still open when its block ended.
+    UnterminatedSection { lang: String },
+    /// A multicode block was still open at the end of the chapter.
+    UnclosedBlock,
+    /// Text between language sections, which is not part of any of them.
+    StrayText,
+    /// A block has a second section for the same language.
+    DuplicateLanguage { lang: String, first_line: usize },
+    /// A language section has no code.
+    EmptySection { lang: String },
+    /// A section marked `run` could not be run.
+    RunFailed { lang: String, reason: String },
+    /// A section header has a word that cannot be a code block attribute,
+    /// usually because mdbook expanded an include header before this ran.
+    InvalidAttribute { word: String },
+    /// The file an `{{#include}}` header points to could not be read.
+    IncludeFailed { path: String, reason: String },
+    /// A block lacks some of the `required-languages`.
+    MissingLanguages { langs: Vec<String> },
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::UnterminatedSection { lang } => {
+                write!(
+                    f,
+                    "section `{lang}` is not closed before the end of its block"
+                )
+            }
+            DiagnosticKind::UnclosedBlock => {
+                write!(f, "multicode block has no closing fence")
+            }
+            DiagnosticKind::StrayText => {
+                write!(f, "text outside of any language section is ignored")
+            }
+            DiagnosticKind::DuplicateLanguage { lang, first_line } => {
+                write!(
+                    f,
+                    "language `{lang}` already has a section at line {first_line} in this block"
+                )
+            }
+            DiagnosticKind::EmptySection { lang } => {
+                write!(f, "section `{lang}` has no code")
+            }
+            DiagnosticKind::RunFailed { lang, reason } => {
+                write!(
+                    f,
+                    "couldn't run section `{lang}`, its output is not shown: {reason}"
+                )
+            }
+            DiagnosticKind::InvalidAttribute { word } => {
+                write!(
+                    f,
+                    "`{word}` in a section header is not a code block attribute; if the header \
+                     had an {{{{#include}}}}, add `before = [\"links\"]` to \
+                     [preprocessor.multicode] so mdbook does not expand it first"
+                )
+            }
+            DiagnosticKind::IncludeFailed { path, reason } => {
+                write!(f, "couldn't include `{path}`: {reason}")
+            }
+            DiagnosticKind::MissingLanguages { langs } => {
+                let langs: Vec<String> = langs.iter().map(|lang| format!("`{lang}`")).collect();
+                write!(f, "multicode block is missing {}", langs.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source_path {
+            Some(path) => write!(f, "{}:", path.display())?,
+            None => write!(f, "<unknown>:")?,
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+/// Collects the diagnostics of a whole book run, so every problem is shown at
+/// once instead of stopping at the first one.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    strict: bool,
+    items: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    /// With `strict`, warnings fail the build just like errors do.
+    pub fn new(strict: bool) -> Diagnostics {
+        Diagnostics {
+            strict,
+            items: Vec::new(),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        source_path: Option<&Path>,
+        line: usize,
+        column: usize,
+        severity: Severity,
+        kind: DiagnosticKind,
+    ) {
+        let severity = if self.strict {
+            Severity::Error
+        } else {
+            severity
+        };
+        self.items.push(Diagnostic {
+            source_path: source_path.map(Path::to_path_buf),
+            line,
+            column,
+            severity,
+            kind,
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.items.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Prints warnings to stderr, and fails listing every error if there was
+    /// any.
+    pub fn finish(self) -> Result<(), Error> {
+        let mut errors = Vec::new();
+        for diagnostic in self.items {
+            match diagnostic.severity {
+                Severity::Warning => eprintln!("Warning: {diagnostic}"),
+                Severity::Error => errors.push(diagnostic.to_string()),
+            }
+        }
+
+        if !errors.is_empty() {
+            anyhow::bail!(
+                "Found {} problem(s) in multicode blocks:\n{}",
+                errors.len(),
+                errors.join("\n")
+            );
+        }
+        Ok(())
+    }
+}
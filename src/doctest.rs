@@ -0,0 +1,103 @@
+use std::path::Path;
+
+use anyhow::Context;
+use mdbook::errors::Error;
+use mdbook::{BookItem, MDBook};
+
+use crate::command::run_code;
+use crate::config::MulticodeConfig;
+use crate::diagnostics::Diagnostics;
+use crate::parser::{parse_with_config, Variant};
+
+/// How many sections [`test`] ran, and how many it left alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TestSummary {
+    pub passed: usize,
+    /// Sections marked `ignore`, `no_run` ones with no check command, and
+    /// languages with no command at all.
+    pub skipped: usize,
+}
+
+/// Runs the code of every language section of the book at `root` through the
+/// `test-commands` configured for its language, printing a line per section.
+///
+/// Sections marked `ignore` are skipped, and `no_run` ones go through
+/// `check-commands` instead, if their language has one. Fails listing every
+/// section whose command failed, with its output, along with any error in
+/// the blocks.
+pub fn test(root: &Path) -> Result<TestSummary, Error> {
+    let book = MDBook::load(root)
+        .with_context(|| format!("Couldn't load the book at {}", root.display()))?;
+    let config = MulticodeConfig::from_book(&book.config)?;
+    let mut diagnostics = Diagnostics::new(config.strict);
+    let src_dir = book.root.join(&book.config.book.src);
+
+    let mut summary = TestSummary::default();
+    let mut failures = Vec::new();
+    for item in book.iter() {
+        let BookItem::Chapter(chapter) = item else {
+            continue;
+        };
+        let source_path = chapter.source_path.as_deref();
+        let chapter_name =
+            source_path.map_or_else(|| chapter.name.clone(), |path| path.display().to_string());
+
+        for block in parse_with_config(
+            &chapter.content,
+            &config,
+            source_path,
+            Some(&src_dir),
+            &mut diagnostics,
+        ) {
+            for variant in &block.variants {
+                let name = format!(
+                    "{chapter_name}:{}:{} {}",
+                    variant.span.line, variant.span.column, variant.lang
+                );
+                let Some(template) = command_for(&config, variant) else {
+                    println!("test {name} ... ignored");
+                    summary.skipped += 1;
+                    continue;
+                };
+
+                let ext = config.extension(&variant.lang);
+                match run_code(template, &variant.body, &ext) {
+                    Ok(_) => {
+                        println!("test {name} ... ok");
+                        summary.passed += 1;
+                    }
+                    Err(e) => {
+                        println!("test {name} ... FAILED");
+                        failures.push(format!("{name}: {e:#}"));
+                    }
+                }
+            }
+        }
+    }
+
+    // Problems in the blocks must not hide the output of failed commands
+    let mut problems = Vec::new();
+    if !failures.is_empty() {
+        problems.push(format!(
+            "{} multicode test(s) failed:\n\n{}",
+            failures.len(),
+            failures.join("\n\n")
+        ));
+    }
+    if let Err(e) = diagnostics.finish() {
+        problems.push(format!("{e:#}"));
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{}", problems.join("\n\n"));
+    }
+    Ok(summary)
+}
+
+/// The command template to run `variant` with, if it is to run at all.
+fn command_for<'a>(config: &'a MulticodeConfig, variant: &Variant) -> Option<&'a str> {
+    let has = |attr: &str| variant.attrs.iter().any(|a| a == attr);
+    if has("ignore") {
+        return None;
+    }
+    config.test_command(&variant.lang, has("no_run"))
+}
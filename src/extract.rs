@@ -0,0 +1,149 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+use mdbook::errors::Error;
+use mdbook::{BookItem, MDBook};
+use serde::Serialize;
+
+use crate::config::MulticodeConfig;
+use crate::diagnostics::Diagnostics;
+use crate::parser::{parse_with_config, BodySource};
+
+/// Name of the manifest written next to the extracted files.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// A file written by [`extract`], as listed in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExtractedFile {
+    /// Path of the file, relative to the output directory.
+    pub file: PathBuf,
+    /// Chapter source file, relative to the book's `src` directory.
+    pub chapter: PathBuf,
+    /// Position of the block in its chapter, starting at 1.
+    pub block: usize,
+    pub lang: String,
+    pub label: String,
+    pub attrs: Vec<String>,
+    /// Line of the section header, or directive, in the chapter.
+    pub line: usize,
+    /// Column of the section header, or directive, in the chapter.
+    pub column: usize,
+    /// Where the code was written: in the chapter, or in the file it was
+    /// included from. `None` when it was put together from several places.
+    pub source: Option<CodeSource>,
+}
+
+/// The lines of a file some extracted code comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CodeSource {
+    /// Relative to the book root, where it is inside it.
+    pub path: PathBuf,
+    /// 1-based, inclusive.
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// Writes the code of every language section of the book at `root` to
+/// `out/<chapter>/block-<n>.<ext>`, and a manifest of them to
+/// `out/manifest.json`.
+///
+/// A block with two sections of the same extension gets `block-<n>-2.<ext>`
+/// for the second one, and so on.
+pub fn extract(root: &Path, out: &Path) -> Result<Vec<ExtractedFile>, Error> {
+    let book = MDBook::load(root)
+        .with_context(|| format!("Couldn't load the book at {}", root.display()))?;
+    let config = MulticodeConfig::from_book(&book.config)?;
+    let mut diagnostics = Diagnostics::new(config.strict);
+    let src_dir = book.root.join(&book.config.book.src);
+
+    let mut files = Vec::new();
+    for item in book.iter() {
+        let BookItem::Chapter(chapter) = item else {
+            continue;
+        };
+        // Draft chapters have no file to name the directory after
+        let Some(source_path) = &chapter.source_path else {
+            continue;
+        };
+
+        let blocks = parse_with_config(
+            &chapter.content,
+            &config,
+            Some(source_path),
+            Some(&src_dir),
+            &mut diagnostics,
+        );
+        for (index, block) in blocks.iter().enumerate() {
+            let mut extensions: Vec<String> = Vec::new();
+            for variant in &block.variants {
+                let ext = config.extension(&variant.lang);
+                let file_name = match extensions.iter().filter(|e| **e == ext).count() {
+                    0 => format!("block-{}.{ext}", index + 1),
+                    n => format!("block-{}-{}.{ext}", index + 1, n + 1),
+                };
+                extensions.push(ext);
+
+                files.push(ExtractedFile {
+                    file: source_path.with_extension("").join(file_name),
+                    chapter: source_path.clone(),
+                    block: index + 1,
+                    lang: variant.lang.clone(),
+                    label: variant.label.clone(),
+                    attrs: variant.attrs.clone(),
+                    line: variant.span.line,
+                    column: variant.span.column,
+                    source: variant.source.as_ref().map(|source| match source {
+                        BodySource::File(include) => CodeSource {
+                            path: relative(&include.path, &book.root),
+                            start_line: *include.lines.start(),
+                            end_line: *include.lines.end(),
+                        },
+                        BodySource::Chapter(lines) => CodeSource {
+                            path: book.config.book.src.join(source_path),
+                            start_line: *lines.start(),
+                            end_line: *lines.end(),
+                        },
+                    }),
+                });
+                write(&out.join(&files.last().unwrap().file), &variant.body)?;
+            }
+        }
+    }
+    diagnostics.finish()?;
+
+    let manifest = serde_json::to_string_pretty(&files)?;
+    write(&out.join(MANIFEST_FILE_NAME), &manifest)?;
+
+    Ok(files)
+}
+
+/// `path` relative to `root`, with `..` resolved, when it is inside it.
+fn relative(path: &Path, root: &Path) -> PathBuf {
+    let normalize = |path: &Path| {
+        let mut normal = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir if normal.file_name().is_some() => {
+                    normal.pop();
+                }
+                component => normal.push(component),
+            }
+        }
+        normal
+    };
+    let path = normalize(path);
+    match path.strip_prefix(normalize(root)) {
+        Ok(inside) => inside.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+fn write(path: &Path, content: &str) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Couldn't create {}", parent.display()))?;
+    }
+    fs::write(path, content).with_context(|| format!("Couldn't write {}", path.display()))
+}
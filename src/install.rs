@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use mdbook::errors::Error;
+use toml_edit::{Array, DocumentMut, Item, Table};
+
+use crate::assets::{install_assets, CSS_FILE_NAME, JS_FILE_NAME};
+use crate::config::{DEPRECATED_NAME, NAME};
+
+/// Sets up the book at `root` to use this preprocessor: adds the
+/// `[preprocessor.multicode]` table, or renames the deprecated
+/// `[preprocessor.http-api]` one, and the asset entries to `book.toml`, and
+/// copies the assets into the theme directory.
+///
+/// The rest of `book.toml` is kept as written, and running it again changes
+/// nothing. Returns a line per change made.
+pub fn install(root: &Path) -> Result<Vec<String>, Error> {
+    let book_toml = root.join("book.toml");
+    let original = fs::read_to_string(&book_toml)
+        .with_context(|| format!("Couldn't read {}", book_toml.display()))?;
+    let mut doc: DocumentMut = original
+        .parse()
+        .with_context(|| format!("Couldn't parse {}", book_toml.display()))?;
+
+    let mut changes = Vec::new();
+
+    let preprocessors = implicit_table(doc.as_table_mut(), "preprocessor")?;
+    // The table keeps its keys and its place in the file under the new name
+    if !preprocessors.contains_key(NAME) {
+        if let Some(table) = preprocessors.remove(DEPRECATED_NAME) {
+            preprocessors.insert(NAME, table);
+            changes.push(format!(
+                "Renamed [preprocessor.{DEPRECATED_NAME}] to [preprocessor.{NAME}] in book.toml"
+            ));
+        }
+    }
+    let added = !preprocessors.contains_key(NAME);
+    if added {
+        let mut table = Table::new();
+        table.insert("command", toml_edit::value("mdbook-multicode"));
+        preprocessors.insert(NAME, Item::Table(table));
+        changes.push(format!("Added [preprocessor.{NAME}] to book.toml"));
+    }
+    // Include headers have to be read before mdbook's links preprocessor
+    // expands them
+    let table = implicit_table(preprocessors, NAME)?;
+    if push_unique(table, "before", "links", &format!("preprocessor.{NAME}"))? && !added {
+        changes.push(format!(
+            "Added \"links\" to preprocessor.{NAME}.before in book.toml"
+        ));
+    }
+
+    let output = implicit_table(doc.as_table_mut(), "output")?;
+    let html = implicit_table(output, "html")?;
+    // A custom theme directory is where the assets belong
+    let theme_dir = html
+        .get("theme")
+        .and_then(Item::as_str)
+        .unwrap_or("theme")
+        .to_owned();
+    for (key, name) in [
+        ("additional-js", JS_FILE_NAME),
+        ("additional-css", CSS_FILE_NAME),
+    ] {
+        let entry = format!("{}/{name}", theme_dir.trim_end_matches('/'));
+        if register(html, key, name, &entry)? {
+            changes.push(format!("Added {entry:?} to output.html.{key} in book.toml"));
+        }
+    }
+
+    let updated = doc.to_string();
+    if updated != original {
+        fs::write(&book_toml, updated)
+            .with_context(|| format!("Couldn't write {}", book_toml.display()))?;
+    }
+
+    let written = install_assets(&root.join(&theme_dir))
+        .with_context(|| format!("Couldn't write the assets into {theme_dir}"))?;
+    changes.extend(
+        written
+            .iter()
+            .map(|path| format!("Wrote {}", relative(path, root).display())),
+    );
+
+    Ok(changes)
+}
+
+/// The sub-table `key` of `parent`, created without a header of its own if
+/// missing, so `[preprocessor.multicode]` does not drag a `[preprocessor]`
+/// along.
+fn implicit_table<'a>(parent: &'a mut Table, key: &str) -> Result<&'a mut Table, Error> {
+    let item = parent.entry(key).or_insert_with(|| {
+        let mut table = Table::new();
+        table.set_implicit(true);
+        Item::Table(table)
+    });
+    item.as_table_mut()
+        .with_context(|| format!("`{key}` in book.toml is not a table"))
+}
+
+/// Adds `entry` to the `key` array of `html`, unless a file called `name` is
+/// already listed. Returns whether it was added.
+fn register(html: &mut Table, key: &str, name: &str, entry: &str) -> Result<bool, Error> {
+    let array = html
+        .entry(key)
+        .or_insert_with(|| toml_edit::value(Array::new()))
+        .as_array_mut()
+        .with_context(|| format!("`output.html.{key}` in book.toml is not an array"))?;
+
+    let listed = array
+        .iter()
+        .filter_map(|value| value.as_str())
+        .any(|path| Path::new(path).file_name().is_some_and(|file| file == name));
+    if listed {
+        return Ok(false);
+    }
+    array.push(entry);
+    Ok(true)
+}
+
+/// Adds `value` to the `key` array of `table`, named `path` in errors, unless
+/// it is already there. Returns whether it was added.
+fn push_unique(table: &mut Table, key: &str, value: &str, path: &str) -> Result<bool, Error> {
+    let array = table
+        .entry(key)
+        .or_insert_with(|| toml_edit::value(Array::new()))
+        .as_array_mut()
+        .with_context(|| format!("`{path}.{key}` in book.toml is not an array"))?;
+
+    if array.iter().any(|item| item.as_str() == Some(value)) {
+        return Ok(false);
+    }
+    array.push(value);
+    Ok(true)
+}
+
+fn relative(path: &Path, root: &Path) -> PathBuf {
+    path.strip_prefix(root).unwrap_or(path).to_path_buf()
+}
@@ -0,0 +1,61 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use mdbook::errors::Error;
+use sha2::{Digest, Sha256};
+
+use crate::command::run_code;
+use crate::config::MulticodeConfig;
+use crate::parser::Variant;
+
+/// Attribute of a language section asking for its output to be shown.
+pub(crate) const RUN_ATTR: &str = "run";
+
+/// What sections marked `run` print, kept on disk so a build only runs the
+/// code that changed.
+#[derive(Debug, Clone)]
+pub(crate) struct OutputCache {
+    dir: PathBuf,
+}
+
+impl OutputCache {
+    pub(crate) fn new(dir: PathBuf) -> OutputCache {
+        OutputCache { dir }
+    }
+
+    /// What `variant` prints to stdout when run with its language's
+    /// `test-commands` entry. Runs are cached by command and code, and only
+    /// when they succeed.
+    pub(crate) fn output(
+        &self,
+        config: &MulticodeConfig,
+        variant: &Variant,
+    ) -> Result<String, Error> {
+        let template = config
+            .test_command(&variant.lang, false)
+            .with_context(|| format!("no entry for `{}` in test-commands", variant.lang))?;
+        let ext = config.extension(&variant.lang);
+
+        let mut hasher = Sha256::new();
+        for part in [template, &ext, &variant.body] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        let key: String = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let path = self.dir.join(key);
+
+        if let Ok(output) = fs::read_to_string(&path) {
+            return Ok(output);
+        }
+        let output = run_code(template, &variant.body, &ext)?;
+        fs::create_dir_all(&self.dir)
+            .and_then(|()| fs::write(&path, &output))
+            .with_context(|| format!("Couldn't write {}", path.display()))?;
+        Ok(output)
+    }
+}
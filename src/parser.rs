@@ -0,0 +1,1150 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+
+use mdbook::utils::{take_anchored_lines, take_lines};
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+
+use crate::config::{DuplicatePolicy, MissingLanguages, MulticodeConfig};
+use crate::container::Container;
+use crate::diagnostics::{DiagnosticKind, Diagnostics, Severity};
+
+/// A multicode block: one code example written in several languages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MulticodeBlock {
+    /// The language sections, in the order they are shown.
+    pub variants: Vec<Variant>,
+    /// From the opening fence to the end of the closing one, or the
+    /// `{{#multicode}}` directive the block was built from.
+    pub span: Span,
+    /// Words of the info string after the `multicode` keyword.
+    pub attrs: Vec<String>,
+    /// Bytes of the chapter the rendered block replaces: from the start of the
+    /// opening fence's line to the end of the closing fence's line.
+    pub(crate) replace: Range<usize>,
+    pub(crate) container: Container,
+}
+
+/// One language section of a multicode block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    /// Language name, as used for highlighting.
+    pub lang: String,
+    /// Text shown in the language picker.
+    pub label: String,
+    /// Code block attributes after the language, like `ignore` or
+    /// `editable`, as mdbook fences take them.
+    pub attrs: Vec<String>,
+    /// The code, each line ending with a line break.
+    pub body: String,
+    /// From the section header to the end of its closing marker.
+    pub span: Span,
+    /// Where the code was read from, `None` when it was put together from
+    /// several places.
+    pub source: Option<BodySource>,
+    /// What the code printed, for sections marked `run`. Filled in by the
+    /// preprocessor when it runs them, never by the parser.
+    pub output: Option<String>,
+}
+
+impl Variant {
+    /// Appends `code` read from `source` to the body, forgetting where the
+    /// body came from once it spans several places.
+    fn push_code(&mut self, code: &str, source: Option<BodySource>) {
+        if code.is_empty() {
+            return;
+        }
+        self.source = if self.body.is_empty() {
+            source
+        } else {
+            match (self.source.take(), source) {
+                (Some(BodySource::Chapter(before)), Some(BodySource::Chapter(after)))
+                    if *after.start() == before.end() + 1 =>
+                {
+                    Some(BodySource::Chapter(*before.start()..=*after.end()))
+                }
+                _ => None,
+            }
+        };
+        self.body.push_str(code);
+    }
+}
+
+/// Where a section's code was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodySource {
+    /// 1-based lines of the chapter the section is in.
+    Chapter(RangeInclusive<usize>),
+    /// An included file, for include headers and lines and
+    /// `{{#multicode}}` directives.
+    File(Include),
+}
+
+/// Lines of a file some code was included from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Include {
+    /// The file, joined to the chapter's directory.
+    pub path: PathBuf,
+    /// 1-based lines of the file the code was taken from.
+    pub lines: RangeInclusive<usize>,
+}
+
+/// Where something was written in a chapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset of the start.
+    pub start: usize,
+    /// Byte offset just past the end.
+    pub end: usize,
+    /// 1-based line of the start.
+    pub line: usize,
+    /// 1-based column of the start.
+    pub column: usize,
+}
+
+/// Finds the multicode blocks of a Markdown document, with the default
+/// configuration. Malformed blocks are recovered from silently; use
+/// [`parse_with_config`] to hear about them.
+pub fn parse(source: &str) -> Vec<MulticodeBlock> {
+    let mut diagnostics = Diagnostics::new(false);
+    parse_with_config(
+        source,
+        &MulticodeConfig::default(),
+        None,
+        None,
+        &mut diagnostics,
+    )
+}
+
+/// Finds the multicode blocks of a chapter, reporting malformed ones to
+/// `diagnostics` against `source_path`.
+///
+/// `source_path` is relative to `src_dir`, the book's source directory, and
+/// together they locate the files `{{#include}}` headers and `{{#multicode}}`
+/// directives point to. Without both, those are reported as errors.
+///
+/// Only real fenced code blocks count, as the HTML renderer will see them, so
+/// fences nested in other code blocks or in HTML comments are left alone.
+pub fn parse_with_config(
+    source: &str,
+    config: &MulticodeConfig,
+    source_path: Option<&Path>,
+    src_dir: Option<&Path>,
+    diagnostics: &mut Diagnostics,
+) -> Vec<MulticodeBlock> {
+    let syntax = Syntax::new(config);
+    let chapter_dir = src_dir
+        .zip(source_path)
+        .and_then(|(src_dir, path)| Some(src_dir.join(path.parent()?)));
+    let lines = LineIndex::new(source);
+    let mut blocks = Vec::new();
+    // Info string and body lines of the block being read
+    let mut open: Option<(Vec<String>, Vec<BodyLine>)> = None;
+    // Line whose text is split over several events, as with CRLF line endings
+    let mut partial: Option<BodyLine> = None;
+
+    // Applies the book-wide rules to the sections of a block found at `range`
+    let finish_block = |range: Range<usize>,
+                        attrs: Vec<String>,
+                        variants: Vec<Variant>,
+                        diagnostics: &mut Diagnostics| {
+        let (line, column) = lines.position(range.start);
+        let variants = sort_variants(
+            config,
+            apply_duplicate_policy(
+                config.duplicate_languages,
+                variants,
+                source_path,
+                diagnostics,
+            ),
+        );
+        let missing = missing_languages(config, &variants);
+        if !missing.is_empty() {
+            let severity = match config.missing_languages {
+                MissingLanguages::Warn => Severity::Warning,
+                MissingLanguages::Error => Severity::Error,
+            };
+            diagnostics.push(
+                source_path,
+                line,
+                column,
+                severity,
+                DiagnosticKind::MissingLanguages { langs: missing },
+            );
+        }
+        let line_start = lines.start_of(line);
+        MulticodeBlock {
+            variants,
+            span: Span {
+                start: range.start,
+                end: range.end,
+                line,
+                column,
+            },
+            attrs,
+            replace: line_start..lines.line_end(range.end),
+            container: Container::from_prefix(&source[line_start..range.start]),
+        }
+    };
+
+    // Block for a directive, if `range` holds nothing else
+    let directive_block = |range: Range<usize>, diagnostics: &mut Diagnostics| {
+        let captures = syntax
+            .directive
+            .captures(source[range.clone()].trim_end())?;
+        let (line, column) = lines.position(range.start);
+        let span = Span {
+            start: range.start,
+            end: range.end,
+            line,
+            column,
+        };
+        let variants = directive_variants(
+            config,
+            chapter_dir.as_deref(),
+            &captures[1],
+            span,
+            source_path,
+            diagnostics,
+        );
+        (!variants.is_empty()).then(|| finish_block(range, Vec::new(), variants, diagnostics))
+    };
+
+    let mut events = Parser::new_ext(source, markdown_options())
+        .into_offset_iter()
+        .peekable();
+    // Whether the event before was the start of a list item
+    let mut item_start = false;
+    while let Some((event, range)) = events.next() {
+        let after_item_start =
+            std::mem::replace(&mut item_start, matches!(event, Event::Start(Tag::Item)));
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let mut words = info_words(&info);
+                if words.first() == Some(&config.fence) {
+                    words.remove(0);
+                    open = Some((words, Vec::new()));
+                }
+            }
+            Event::Start(Tag::Paragraph) => {
+                blocks.extend(directive_block(range, diagnostics));
+            }
+            // Items of a tight list hold their text with no paragraph around
+            // it, so a directive there is the whole item up to its sublist
+            Event::Text(_)
+                if open.is_none()
+                    && after_item_start
+                    && matches!(
+                        events.peek(),
+                        Some((Event::End(TagEnd::Item) | Event::Start(Tag::List(_)), _))
+                    ) =>
+            {
+                blocks.extend(directive_block(range, diagnostics));
+            }
+            Event::Text(text) => {
+                if let Some((_, body)) = &mut open {
+                    let (mut line, mut column) = lines.position(range.start);
+                    let mut start = range.start;
+                    for piece in text.split_inclusive('\n') {
+                        let text = piece.strip_suffix('\n').unwrap_or(piece);
+                        let text = text.strip_suffix('\r').unwrap_or(text);
+                        let mut body_line = partial.take().unwrap_or_else(|| BodyLine {
+                            span: Span {
+                                start,
+                                end: lines.line_end(start),
+                                line,
+                                column,
+                            },
+                            text: String::new(),
+                        });
+                        body_line.text.push_str(text);
+
+                        if !piece.ends_with('\n') {
+                            partial = Some(body_line);
+                            continue;
+                        }
+                        body.push(body_line);
+                        // Lines after the event's first one start with no
+                        // container markers left to skip
+                        line += 1;
+                        start = lines.start_of(line);
+                        column = 1;
+                    }
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some((attrs, mut body)) = open.take() {
+                    body.extend(partial.take());
+                    let fenced = &source[range.clone()];
+                    let open_fence: String = fenced
+                        .chars()
+                        .take_while(|c| *c == '`' || *c == '~')
+                        .collect();
+                    let (line, column) = lines.position(range.start);
+                    let closed = syntax.is_closed(fenced, &open_fence);
+                    if !closed {
+                        diagnostics.push(
+                            source_path,
+                            line,
+                            column,
+                            Severity::Warning,
+                            DiagnosticKind::UnclosedBlock,
+                        );
+                    }
+
+                    let variants = parse_variants(
+                        config,
+                        &syntax,
+                        &body,
+                        closed,
+                        source_path,
+                        chapter_dir.as_deref(),
+                        diagnostics,
+                    );
+                    blocks.push(finish_block(range, attrs, variants, diagnostics));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    blocks
+}
+
+/// Section markers built from the book's configuration.
+struct Syntax {
+    code_start: Regex,
+    code_end: Regex,
+    closing_fence: Regex,
+    include: Regex,
+    body_include: Regex,
+    directive: Regex,
+}
+
+/// A line of a block's body, without the markers of its container.
+struct BodyLine {
+    span: Span,
+    text: String,
+}
+
+enum ParseState {
+    Multicode,
+    Code,
+}
+
+impl Syntax {
+    fn new(config: &MulticodeConfig) -> Syntax {
+        Syntax {
+            code_start: Regex::new(&format!(
+                r"^{} ([a-zA-Z0-9][a-zA-Z0-9+#._-]*(?:[ \t,].*)?)$",
+                regex::escape(&config.section_start)
+            ))
+            .unwrap(),
+            code_end: Regex::new(&format!("^{}$", regex::escape(&config.section_end))).unwrap(),
+            closing_fence: Regex::new(r"^(`{3,}|~{3,})[ \t]*$").unwrap(),
+            include: Regex::new(r"\{\{\s*#include\s+([^}]+?)\s*\}\}").unwrap(),
+            body_include: Regex::new(r"^\{\{\s*#include\s+([^}]+?)\s*\}\}$").unwrap(),
+            directive: Regex::new(r"^\{\{\s*#multicode\s+([^}\s]+)\s*\}\}$").unwrap(),
+        }
+    }
+
+    /// Whether the last line of a fenced block's source is a fence closing
+    /// `open_fence`: same character, at least as many of them.
+    fn is_closed(&self, fenced: &str, open_fence: &str) -> bool {
+        let Some((_, last_line)) = fenced.trim_end_matches(['\r', '\n']).rsplit_once('\n') else {
+            return false;
+        };
+        let last_line = last_line.trim_start_matches([' ', '\t', '>']);
+        self.closing_fence
+            .captures(last_line)
+            .is_some_and(|captures| {
+                let fence = &captures[1];
+                fence.len() >= open_fence.len() && fence[..1] == open_fence[..1]
+            })
+    }
+}
+
+/// Splits an info string the way mdbook does, on commas and whitespace.
+fn info_words(info: &str) -> Vec<String> {
+    info.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Whether `word` can be a code block attribute, like `ignore` or
+/// `hidelines=#`, rather than a piece of code.
+fn is_attribute(word: &str) -> bool {
+    !word.contains([
+        '"', '\'', '`', '<', '>', '(', ')', '{', '}', '[', ']', ';', ':', '/', '\\',
+    ])
+}
+
+/// Takes the `"quoted"` label out of a section header, if it has one.
+fn split_label(header: &str) -> (String, Option<String>) {
+    let Some(open) = header.find('"') else {
+        return (header.to_owned(), None);
+    };
+    let Some(len) = header[open + 1..].find('"') else {
+        return (header.to_owned(), None);
+    };
+    let close = open + 1 + len;
+    let info = format!("{} {}", &header[..open], &header[close + 1..]);
+    (info, Some(header[open + 1..close].to_owned()))
+}
+
+/// Reads the code an `{{#include}}` header points to, relative to
+/// `chapter_dir`, as mdbook's own `{{#include}}` does: `path`, `path:anchor`,
+/// or `path:start:end` with 1-based lines, either end left out for the
+/// beginning or end of the file.
+fn read_include(chapter_dir: Option<&Path>, spec: &str) -> Result<(String, Include), String> {
+    let dir = chapter_dir.ok_or("the chapter has no file to resolve it from")?;
+    let (path, selector) = match spec.split_once(':') {
+        Some((path, selector)) => (path, Some(selector)),
+        None => (spec, None),
+    };
+    let path: PathBuf = dir.join(path);
+    let content = fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let count = content.lines().count();
+
+    let mut parts = selector.unwrap_or("").splitn(2, ':');
+    let start = match parts.next().unwrap_or("") {
+        "" => None,
+        first => match first.parse::<usize>() {
+            Ok(line) => Some(line.saturating_sub(1)),
+            Err(_) => {
+                let code = with_final_newline(take_anchored_lines(&content, first));
+                let lines = anchor_lines(&content, first);
+                return Ok((code, Include { path, lines }));
+            }
+        },
+    };
+    let range = match (start, parts.next().map(str::parse::<usize>)) {
+        (Some(start), Some(Ok(end))) => start..end,
+        (Some(start), Some(Err(_))) => start..count,
+        (Some(start), None) => start..start + 1,
+        (None, Some(Ok(end))) => 0..end,
+        (None, _) => 0..count,
+    };
+    let code = with_final_newline(take_lines(&content, range.clone()));
+    let lines = range.start + 1..=range.end.min(count);
+    Ok((code, Include { path, lines }))
+}
+
+/// The 1-based lines between the markers of `anchor` in `content`, or an
+/// empty range if it has none.
+fn anchor_lines(content: &str, anchor: &str) -> RangeInclusive<usize> {
+    let marks = |marker: &str| {
+        let pattern = format!(r"{marker}:\s*{}(?:[^\w-]|$)", regex::escape(anchor));
+        Regex::new(&pattern).unwrap()
+    };
+    let (start, end) = (marks("ANCHOR"), marks("ANCHOR_END"));
+    let lines: Vec<&str> = content.lines().collect();
+
+    let Some(first) = lines.iter().position(|line| start.is_match(line)) else {
+        return RangeInclusive::new(1, 0);
+    };
+    let last = lines[first + 1..]
+        .iter()
+        .position(|line| end.is_match(line))
+        .map_or(lines.len(), |i| first + 1 + i);
+    first + 2..=last
+}
+
+/// Sections for a `{{#multicode path}}` directive: one per file next to the
+/// chapter named like `path` with an extension that names a language, the
+/// chapter itself aside. Files are taken in name order.
+fn directive_variants(
+    config: &MulticodeConfig,
+    chapter_dir: Option<&Path>,
+    path: &str,
+    span: Span,
+    source_path: Option<&Path>,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Variant> {
+    let mut report = |reason: String| {
+        diagnostics.push(
+            source_path,
+            span.line,
+            span.column,
+            Severity::Error,
+            DiagnosticKind::IncludeFailed {
+                path: path.to_owned(),
+                reason,
+            },
+        )
+    };
+    let Some(chapter_dir) = chapter_dir else {
+        report("the chapter has no file to resolve it from".to_owned());
+        return Vec::new();
+    };
+    let base = chapter_dir.join(path);
+    let (Some(dir), Some(stem)) = (base.parent(), base.file_name()) else {
+        report("not a file name".to_owned());
+        return Vec::new();
+    };
+
+    // The chapter itself may be named like its examples
+    let chapter_file = source_path
+        .and_then(Path::file_name)
+        .and_then(|name| fs::canonicalize(chapter_dir.join(name)).ok());
+    let mut files: Vec<(PathBuf, String)> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|file| file.is_file() && file.file_stem() == Some(stem))
+            .filter(|file| chapter_file.is_none() || fs::canonicalize(file).ok() != chapter_file)
+            .filter_map(|file| {
+                let ext = file.extension()?.to_str()?;
+                let lang = config.language_for_extension(ext)?;
+                Some((file, lang))
+            })
+            .collect(),
+        Err(e) => {
+            report(format!("{}: {e}", dir.display()));
+            return Vec::new();
+        }
+    };
+    if files.is_empty() {
+        report(format!(
+            "no file of a known language matches {}.*",
+            base.display()
+        ));
+        return Vec::new();
+    }
+    files.sort();
+
+    let mut variants = Vec::new();
+    for (file, lang) in files {
+        let body = match fs::read_to_string(&file) {
+            Ok(body) => with_final_newline(body),
+            Err(e) => {
+                report(format!("{}: {e}", file.display()));
+                continue;
+            }
+        };
+        variants.push(Variant {
+            label: config
+                .label_for(&lang)
+                .map_or_else(|| lang.clone(), str::to_owned),
+            lang,
+            attrs: Vec::new(),
+            source: Some(BodySource::File(Include {
+                lines: 1..=body.lines().count(),
+                path: file,
+            })),
+            body,
+            span,
+            output: None,
+        });
+    }
+    variants
+}
+
+/// Section bodies have each line end with a line break, the last one too.
+fn with_final_newline(mut text: String) -> String {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+/// Splits a block's body into its language sections.
+fn parse_variants(
+    config: &MulticodeConfig,
+    syntax: &Syntax,
+    body: &[BodyLine],
+    closed: bool,
+    source_path: Option<&Path>,
+    chapter_dir: Option<&Path>,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Variant> {
+    let mut variants: Vec<Variant> = Vec::new();
+    // Sections whose include failed, already reported
+    let mut failed = Vec::new();
+
+    let mut parse_state = ParseState::Multicode;
+    let mut after_include = false;
+    for line in body {
+        // An include header may be followed by a closing marker, or not
+        if std::mem::take(&mut after_include) && syntax.code_end.is_match(&line.text) {
+            variants.last_mut().unwrap().span.end = line.span.end;
+            continue;
+        }
+
+        match parse_state {
+            ParseState::Multicode => {
+                if let Some(captures) = syntax.code_start.captures(&line.text) {
+                    let mut header = captures[1].to_owned();
+                    let include = syntax.include.captures(&captures[1]).map(|include| {
+                        header.replace_range(include.get(0).unwrap().range(), "");
+                        include[1].to_owned()
+                    });
+                    let (info, label) = split_label(&header);
+                    let mut attrs = info_words(&info);
+                    let lang = config.canonical_language(&attrs.remove(0));
+                    // Code in the header, most likely put there by mdbook
+                    // expanding an include header before this ran
+                    if let Some(word) = attrs.iter().find(|word| !is_attribute(word)) {
+                        diagnostics.push(
+                            source_path,
+                            line.span.line,
+                            line.span.column,
+                            Severity::Error,
+                            DiagnosticKind::InvalidAttribute { word: word.clone() },
+                        );
+                        attrs.retain(|word| is_attribute(word));
+                    }
+                    let label = label
+                        .or_else(|| config.label_for(&lang).map(str::to_owned))
+                        .unwrap_or_else(|| lang.clone());
+                    variants.push(Variant {
+                        label,
+                        lang,
+                        attrs,
+                        body: String::new(),
+                        span: line.span,
+                        source: None,
+                        output: None,
+                    });
+
+                    if let Some(spec) = include {
+                        match read_include(chapter_dir, &spec) {
+                            Ok((code, include)) => variants
+                                .last_mut()
+                                .unwrap()
+                                .push_code(&code, Some(BodySource::File(include))),
+                            Err(reason) => {
+                                failed.push(variants.len() - 1);
+                                diagnostics.push(
+                                    source_path,
+                                    line.span.line,
+                                    line.span.column,
+                                    Severity::Error,
+                                    DiagnosticKind::IncludeFailed { path: spec, reason },
+                                );
+                            }
+                        }
+                        after_include = true;
+                    } else {
+                        parse_state = ParseState::Code;
+                    }
+                } else if let Some(offset) = line.text.find(|c: char| !c.is_whitespace()) {
+                    diagnostics.push(
+                        source_path,
+                        line.span.line,
+                        line.span.column + offset,
+                        Severity::Warning,
+                        DiagnosticKind::StrayText,
+                    );
+                }
+            }
+            ParseState::Code => {
+                let variant = variants.last_mut().unwrap();
+                variant.span.end = line.span.end;
+                if syntax.code_end.is_match(&line.text) {
+                    parse_state = ParseState::Multicode;
+                } else if let Some(captures) = syntax.body_include.captures(line.text.trim()) {
+                    // Read here, as mdbook would expand it without escaping
+                    // it once this has turned the section into HTML
+                    match read_include(chapter_dir, &captures[1]) {
+                        Ok((code, include)) => {
+                            variant.push_code(&code, Some(BodySource::File(include)))
+                        }
+                        Err(reason) => diagnostics.push(
+                            source_path,
+                            line.span.line,
+                            line.span.column,
+                            Severity::Error,
+                            DiagnosticKind::IncludeFailed {
+                                path: captures[1].to_owned(),
+                                reason,
+                            },
+                        ),
+                    }
+                } else {
+                    let lines = line.span.line..=line.span.line;
+                    variant.push_code(
+                        &format!("{}\n", line.text),
+                        Some(BodySource::Chapter(lines)),
+                    );
+                }
+            }
+        }
+    } // End of parsing
+
+    // An unclosed block has already been reported, which covers the section
+    // left open by it
+    if let (ParseState::Code, true) = (parse_state, closed) {
+        let variant = variants.last().unwrap();
+        diagnostics.push(
+            source_path,
+            variant.span.line,
+            variant.span.column,
+            Severity::Warning,
+            DiagnosticKind::UnterminatedSection {
+                lang: variant.lang.clone(),
+            },
+        );
+    }
+
+    for (i, variant) in variants.iter().enumerate() {
+        if variant.body.trim().is_empty() && !failed.contains(&i) {
+            diagnostics.push(
+                source_path,
+                variant.span.line,
+                variant.span.column,
+                Severity::Warning,
+                DiagnosticKind::EmptySection {
+                    lang: variant.lang.clone(),
+                },
+            );
+        }
+    }
+
+    variants
+}
+
+/// Resolves languages that have more than one section with the same label in
+/// the same block. Sections given labels of their own are told apart by them.
+fn apply_duplicate_policy(
+    policy: DuplicatePolicy,
+    variants: Vec<Variant>,
+    source_path: Option<&Path>,
+    diagnostics: &mut Diagnostics,
+) -> Vec<Variant> {
+    let mut kept: Vec<Variant> = Vec::with_capacity(variants.len());
+    // Sections seen per language and label, before any renaming
+    let mut seen: HashMap<(String, String), usize> = HashMap::new();
+    for variant in variants {
+        let n = seen
+            .entry((variant.lang.clone(), variant.label.clone()))
+            .or_default();
+        *n += 1;
+        let n = *n;
+
+        let Some(first) = kept
+            .iter()
+            .position(|v| v.lang == variant.lang && v.label == variant.label)
+        else {
+            kept.push(variant);
+            continue;
+        };
+
+        match policy {
+            DuplicatePolicy::Error => diagnostics.push(
+                source_path,
+                variant.span.line,
+                variant.span.column,
+                Severity::Error,
+                DiagnosticKind::DuplicateLanguage {
+                    lang: variant.lang,
+                    first_line: kept[first].span.line,
+                },
+            ),
+            DuplicatePolicy::Merge => kept[first].push_code(&variant.body, variant.source),
+            DuplicatePolicy::KeepFirst => {}
+            DuplicatePolicy::KeepLast => {
+                kept[first].body = variant.body;
+                kept[first].source = variant.source;
+            }
+            DuplicatePolicy::Allow => {
+                let mut variant = variant;
+                variant.label = format!("{} ({n})", variant.label);
+                kept.push(variant);
+            }
+        }
+    }
+    kept
+}
+
+/// Puts the variants in the order of the `languages` list. Languages not in it
+/// come after, in the order they were written.
+fn sort_variants(config: &MulticodeConfig, mut variants: Vec<Variant>) -> Vec<Variant> {
+    if config.languages.is_empty() {
+        return variants;
+    }
+    let order: Vec<String> = config
+        .languages
+        .iter()
+        .map(|lang| config.canonical_language(lang))
+        .collect();
+    variants.sort_by_key(|variant| {
+        order
+            .iter()
+            .position(|lang| *lang == variant.lang)
+            .unwrap_or(order.len())
+    });
+    variants
+}
+
+/// The `required-languages` a block has no section for.
+fn missing_languages(config: &MulticodeConfig, variants: &[Variant]) -> Vec<String> {
+    config
+        .required_languages
+        .iter()
+        .map(|lang| config.canonical_language(lang))
+        .filter(|lang| !variants.iter().any(|variant| variant.lang == *lang))
+        .collect()
+}
+
+/// The extensions mdbook's HTML renderer parses chapters with, so blocks are
+/// found exactly where it would find them.
+fn markdown_options() -> Options {
+    let mut opts = Options::empty();
+    opts.insert(Options::ENABLE_TABLES);
+    opts.insert(Options::ENABLE_FOOTNOTES);
+    opts.insert(Options::ENABLE_STRIKETHROUGH);
+    opts.insert(Options::ENABLE_TASKLISTS);
+    opts.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+    opts
+}
+
+/// Maps byte offsets of a chapter to 1-based line and column numbers.
+struct LineIndex<'a> {
+    content: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(content: &'a str) -> LineIndex<'a> {
+        let starts = std::iter::once(0)
+            .chain(content.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { content, starts }
+    }
+
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|start| *start <= offset);
+        let column = self.content[self.starts[line - 1]..offset].chars().count() + 1;
+        (line, column)
+    }
+
+    /// Byte offset of the start of a 1-based line.
+    fn start_of(&self, line: usize) -> usize {
+        self.starts
+            .get(line - 1)
+            .copied()
+            .unwrap_or(self.content.len())
+    }
+
+    /// End of the line containing `offset`, including its line break. An
+    /// offset right after a line break is already there.
+    fn line_end(&self, offset: usize) -> usize {
+        if self.content[..offset].ends_with('\n') {
+            return offset;
+        }
+        match self.content[offset..].find('\n') {
+            Some(i) => offset + i + 1,
+            None => self.content.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bodies(source: &str) -> Vec<Vec<(String, String)>> {
+        parse(source)
+            .into_iter()
+            .map(|block| {
+                block
+                    .variants
+                    .into_iter()
+                    .map(|variant| (variant.lang, variant.body))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn variant(lang: &str, body: &str) -> (String, String) {
+        (lang.to_owned(), body.to_owned())
+    }
+
+    fn diagnose(source: &str) -> Vec<(usize, usize, DiagnosticKind)> {
+        let mut diagnostics = Diagnostics::new(false);
+        parse_with_config(
+            source,
+            &MulticodeConfig::default(),
+            None,
+            None,
+            &mut diagnostics,
+        );
+        diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.kind.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn sections() {
+        let source = "# Title\n\n```multicode\n>>>>> rust\nfn main() {}\n<<<<<\n>>>>> cpp\nint main() {}\n<<<<<\n```\n";
+        assert_eq!(
+            bodies(source),
+            [[
+                variant("rust", "fn main() {}\n"),
+                variant("cpp", "int main() {}\n")
+            ]]
+        );
+        assert!(diagnose(source).is_empty());
+    }
+
+    #[test]
+    fn fence_nested_in_a_longer_fence() {
+        let source = "````multicode\n>>>>> markdown\n```rust\nfn main() {}\n```\n<<<<<\n````\n";
+        assert_eq!(
+            bodies(source),
+            [[variant("markdown", "```rust\nfn main() {}\n```\n")]]
+        );
+    }
+
+    #[test]
+    fn list_items_and_blockquotes() {
+        let listed = "- item\n\n  ```multicode\n  >>>>> rust\n  a\n\n  b\n  <<<<<\n  ```\n";
+        let blocks = parse(listed);
+        assert_eq!(blocks[0].variants[0].body, "a\n\nb\n");
+        assert_eq!(
+            blocks[0].container.wrap("<div>\n</div>\n"),
+            "\n  <div>\n  </div>\n"
+        );
+        assert_eq!(blocks[0].span.line, 3);
+        assert_eq!(blocks[0].span.column, 3);
+
+        let marked = "- ```multicode\n  >>>>> rust\n  a\n  <<<<<\n  ```\n";
+        let blocks = parse(marked);
+        assert_eq!(blocks[0].variants[0].body, "a\n");
+        assert_eq!(
+            blocks[0].container.wrap("<div>\n</div>\n"),
+            "- <div>\n  </div>\n"
+        );
+
+        let quoted = "> ```multicode\n> >>>>> rust\n> a\n>\n> <<<<<\n> ```\n";
+        let blocks = parse(quoted);
+        assert_eq!(blocks[0].variants[0].body, "a\n\n");
+        assert_eq!(
+            blocks[0].container.wrap("<div>\n\n</div>\n"),
+            ">\n> <div>\n>\n> </div>\n"
+        );
+    }
+
+    #[test]
+    fn fences_in_comments_and_code_blocks_are_left_alone() {
+        let commented = "<!--\n```multicode\n>>>>> rust\na\n<<<<<\n```\n-->\n";
+        assert!(parse(commented).is_empty());
+
+        let shown = "````markdown\n```multicode\n>>>>> rust\na\n<<<<<\n```\n````\n";
+        assert!(parse(shown).is_empty());
+    }
+
+    #[test]
+    fn unclosed_block() {
+        let source = "```multicode\n>>>>> rust\na\n";
+        assert_eq!(bodies(source), [[variant("rust", "a\n")]]);
+        // The section left open is covered by the block's own report
+        assert_eq!(diagnose(source), [(1, 1, DiagnosticKind::UnclosedBlock)]);
+    }
+
+    #[test]
+    fn unterminated_section() {
+        let source = "```multicode\n>>>>> rust\na\n```\n";
+        assert_eq!(bodies(source), [[variant("rust", "a\n")]]);
+        assert_eq!(
+            diagnose(source),
+            [(
+                2,
+                1,
+                DiagnosticKind::UnterminatedSection {
+                    lang: "rust".to_owned()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn stray_text() {
+        let source = "```multicode\n>>>>> rust\na\n<<<<<\n  oops\n```\n";
+        assert_eq!(bodies(source), [[variant("rust", "a\n")]]);
+        assert_eq!(diagnose(source), [(5, 3, DiagnosticKind::StrayText)]);
+    }
+
+    #[test]
+    fn duplicate_languages_allowed() {
+        let config = MulticodeConfig {
+            duplicate_languages: DuplicatePolicy::Allow,
+            ..MulticodeConfig::default()
+        };
+        let source =
+            "```multicode\n>>>>> rust\na\n<<<<<\n>>>>> rust\nb\n<<<<<\n>>>>> rust\nc\n<<<<<\n```\n";
+        let mut diagnostics = Diagnostics::new(false);
+        let blocks = parse_with_config(source, &config, None, None, &mut diagnostics);
+        let labels: Vec<&str> = blocks[0]
+            .variants
+            .iter()
+            .map(|v| v.label.as_str())
+            .collect();
+        assert_eq!(labels, ["rust", "rust (2)", "rust (3)"]);
+    }
+
+    #[test]
+    fn sources_of_duplicate_languages() {
+        let source = "```multicode\n>>>>> rust\na\nb\n<<<<<\n>>>>> rust\nc\n<<<<<\n```\n";
+        let sources = |policy| {
+            let config = MulticodeConfig {
+                duplicate_languages: policy,
+                ..MulticodeConfig::default()
+            };
+            let mut diagnostics = Diagnostics::new(false);
+            let blocks = parse_with_config(source, &config, None, None, &mut diagnostics);
+            blocks[0].variants[0].source.clone()
+        };
+        assert_eq!(
+            sources(DuplicatePolicy::KeepFirst),
+            Some(BodySource::Chapter(3..=4))
+        );
+        assert_eq!(
+            sources(DuplicatePolicy::KeepLast),
+            Some(BodySource::Chapter(7..=7))
+        );
+        assert_eq!(sources(DuplicatePolicy::Merge), None);
+    }
+
+    #[test]
+    fn include_expanded_too_early() {
+        let source = "```multicode\n>>>>> rust fn main() {\n}\n<<<<<\n```\n";
+        let blocks = parse(source);
+        assert_eq!(blocks[0].variants[0].attrs, ["fn"]);
+        assert_eq!(
+            diagnose(source),
+            [(
+                2,
+                1,
+                DiagnosticKind::InvalidAttribute {
+                    word: "main()".to_owned()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("sort.rs"),
+            "// ANCHOR: sort\nfn sort() {}\n// ANCHOR_END: sort\nfn main() {}\n",
+        )
+        .unwrap();
+        let source = "```multicode\n>>>>> rust \"Header\" {{#include sort.rs:sort}}\n>>>>> rust \"Lines\" {{#include sort.rs:4}}\n<<<<<\n>>>>> rust \"Body\"\n// main\n{{#include sort.rs:4:}}\n<<<<<\n```\n";
+        let mut diagnostics = Diagnostics::new(false);
+        let blocks = parse_with_config(
+            source,
+            &MulticodeConfig::default(),
+            Some(Path::new("chapter.md")),
+            Some(dir.path()),
+            &mut diagnostics,
+        );
+        assert!(diagnostics.is_empty());
+        let lines: Vec<_> = blocks[0]
+            .variants
+            .iter()
+            .map(|v| match &v.source {
+                Some(BodySource::File(include)) => Some(include.lines.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lines, [Some(2..=2), Some(4..=4), None]);
+        let bodies: Vec<&str> = blocks[0].variants.iter().map(|v| v.body.as_str()).collect();
+        assert_eq!(
+            bodies,
+            [
+                "fn sort() {}\n",
+                "fn main() {}\n",
+                "// main\nfn main() {}\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn directive() {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, content) in [
+            ("sort.rs", "fn main() {}\n"),
+            ("sort.cc", "int main() {}\n"),
+            ("sort.txt", "notes\n"),
+            ("sort.md", "{{#multicode sort}}\n"),
+        ] {
+            fs::write(dir.path().join(file), content).unwrap();
+        }
+        let mut diagnostics = Diagnostics::new(false);
+        let blocks = parse_with_config(
+            "{{#multicode sort}}\n",
+            &MulticodeConfig::default(),
+            Some(Path::new("sort.md")),
+            Some(dir.path()),
+            &mut diagnostics,
+        );
+        assert!(diagnostics.is_empty());
+        let langs: Vec<&str> = blocks[0].variants.iter().map(|v| v.lang.as_str()).collect();
+        assert_eq!(langs, ["cpp", "rust"]);
+
+        let tight =
+            "- {{#multicode sort}}\n- {{#multicode sort}} too\n- {{#multicode sort}}\n  - sub\n";
+        let blocks = parse_with_config(
+            tight,
+            &MulticodeConfig::default(),
+            Some(Path::new("sort.md")),
+            Some(dir.path()),
+            &mut diagnostics,
+        );
+        assert!(diagnostics.is_empty());
+        let replaced: Vec<&str> = blocks.iter().map(|b| &tight[b.replace.clone()]).collect();
+        assert_eq!(
+            replaced,
+            ["- {{#multicode sort}}\n", "- {{#multicode sort}}\n"]
+        );
+    }
+
+    #[test]
+    fn sources_of_body_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("inc.rs"), "fn a() {}\nfn b() {}\n").unwrap();
+        let source = "```multicode\n>>>>> rust\n{{#include inc.rs}}\n<<<<<\n>>>>> python\nx = 1\n\n{{#include inc.rs}}\n<<<<<\n```\n";
+        let mut diagnostics = Diagnostics::new(false);
+        let blocks = parse_with_config(
+            source,
+            &MulticodeConfig::default(),
+            Some(Path::new("chapter.md")),
+            Some(dir.path()),
+            &mut diagnostics,
+        );
+        assert!(diagnostics.is_empty());
+        let sources: Vec<_> = blocks[0]
+            .variants
+            .iter()
+            .map(|v| v.source.clone())
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                Some(BodySource::File(Include {
+                    path: dir.path().join("inc.rs"),
+                    lines: 1..=2,
+                })),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let source = "```multicode\r\n>>>>> rust\r\na\r\n\r\nb\r\n<<<<<\r\n```\r\n";
+        assert_eq!(
+            bodies(source),
+            [[("rust".to_owned(), "a\n\nb\n".to_owned())]]
+        );
+
+        let quoted = "> ```multicode\r\n> >>>>> rust\r\n> a\r\n>\r\n> <<<<<\r\n> ```\r\n";
+        assert_eq!(bodies(quoted), [[("rust".to_owned(), "a\n\n".to_owned())]]);
+    }
+}
@@ -0,0 +1,342 @@
+use crate::config::{CodeBlocks, MulticodeConfig};
+use crate::parser::{MulticodeBlock, Variant};
+
+/// Turns a parsed multicode block into the text that replaces it in the
+/// chapter, usually HTML.
+///
+/// The output is re-indented afterwards to stay inside the list item or
+/// blockquote the block was written in, so it should not try to match it.
+pub trait MulticodeRenderer {
+    fn render(&self, block: &MulticodeBlock, ctx: &RenderContext<'_>) -> String;
+}
+
+/// What a renderer knows about the block being rendered.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderContext<'a> {
+    /// Position of the block in its chapter, starting at 0. Useful to keep DOM
+    /// ids unique on the page.
+    pub index: usize,
+    pub config: &'a MulticodeConfig,
+}
+
+/// The default renderer: a `<select>` dropdown above the code, which shows one
+/// language at a time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SelectRenderer;
+
+/// A row of tab buttons above the code, one per language.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TabsRenderer;
+
+impl MulticodeRenderer for SelectRenderer {
+    fn render(&self, block: &MulticodeBlock, ctx: &RenderContext<'_>) -> String {
+        let mut new_content = String::new();
+        let variants = &block.variants;
+        if variants.is_empty() {
+            return new_content;
+        }
+
+        let example_class_name = format!("code-example-tab-{}", ctx.index);
+        let keys = variant_keys(block);
+        let first_key = &keys[default_variant(block, ctx)];
+        let sticky = sticky_arg(ctx);
+
+        let lang_select_options = variants
+            .iter()
+            .zip(&keys)
+            .map(|(variant, key)| {
+                let label = html_escape(&variant.label);
+                let selected = if key == first_key { " selected" } else { "" };
+                format!(r#"<option value="{example_class_name}-{key}"{selected}>{label}</option>"#)
+            })
+            .fold(String::new(), |mut acc, s| {
+                acc.push_str(&s);
+                acc
+            });
+
+        new_content.push_str(&format!(
+            r#"<div><select onchange="changeCodeExample('{example_class_name}', event.target.value{sticky})" value="{first_key}" class="code-example" data-group="{example_class_name}" autocomplete="off">"#
+        ));
+        new_content.push_str(&lang_select_options);
+        new_content.push_str(r#"</select></div>"#);
+        new_content.push('\n');
+
+        push_code_panes(
+            block,
+            ctx,
+            &keys,
+            first_key,
+            &example_class_name,
+            &mut new_content,
+        );
+        new_content
+    }
+}
+
+impl MulticodeRenderer for TabsRenderer {
+    fn render(&self, block: &MulticodeBlock, ctx: &RenderContext<'_>) -> String {
+        let mut new_content = String::new();
+        let variants = &block.variants;
+        if variants.is_empty() {
+            return new_content;
+        }
+
+        let example_class_name = format!("code-example-tab-{}", ctx.index);
+        let keys = variant_keys(block);
+        let first_key = &keys[default_variant(block, ctx)];
+        let sticky = sticky_arg(ctx);
+
+        new_content.push_str(r#"<div class="code-example-tabs" role="tablist">"#);
+        for (variant, key) in variants.iter().zip(&keys) {
+            let label = html_escape(&variant.label);
+            new_content.push_str(&format!(
+                r#"<button type="button" role="tab" class="code-example-tab" data-group="{example_class_name}" data-target="{example_class_name}-{key}" onclick="changeCodeExample('{example_class_name}', '{example_class_name}-{key}'{sticky})">{label}</button>"#
+            ));
+        }
+        new_content.push_str(r#"</div>"#);
+        new_content.push('\n');
+
+        push_code_panes(
+            block,
+            ctx,
+            &keys,
+            first_key,
+            &example_class_name,
+            &mut new_content,
+        );
+        new_content
+    }
+}
+
+/// Keys that tell the variants of a block apart in DOM ids: the language,
+/// numbered from its second section in the block on.
+fn variant_keys(block: &MulticodeBlock) -> Vec<String> {
+    let variants = &block.variants;
+    variants
+        .iter()
+        .enumerate()
+        .map(|(i, variant)| {
+            let lang = &variant.lang;
+            match variants[..i].iter().filter(|v| &v.lang == lang).count() {
+                0 => lang.clone(),
+                n => format!("{lang}-{}", n + 1),
+            }
+        })
+        .collect()
+}
+
+/// Extra argument to `changeCodeExample` that makes a pick apply to every
+/// block, and be remembered.
+fn sticky_arg(ctx: &RenderContext<'_>) -> &'static str {
+    if ctx.config.sticky {
+        ", true"
+    } else {
+        ""
+    }
+}
+
+/// Index of the variant shown before the reader picks one.
+fn default_variant(block: &MulticodeBlock, ctx: &RenderContext<'_>) -> usize {
+    ctx.config
+        .default_language
+        .as_ref()
+        .map(|lang| ctx.config.canonical_language(lang))
+        .and_then(|lang| block.variants.iter().position(|v| v.lang == lang))
+        .unwrap_or(0)
+}
+
+/// Pushes one `<div>` of code per variant, and the script showing the
+/// default one.
+fn push_code_panes(
+    block: &MulticodeBlock,
+    ctx: &RenderContext<'_>,
+    keys: &[String],
+    first_key: &str,
+    example_class_name: &str,
+    new_content: &mut String,
+) {
+    for (variant, key) in block.variants.iter().zip(keys) {
+        let lang = &variant.lang;
+        match ctx.config.code_blocks {
+            CodeBlocks::Html => {
+                // Attributes become classes, as mdbook does for its own fences
+                let classes: String = variant
+                    .attrs
+                    .iter()
+                    .map(|attr| format!(" {}", html_escape(attr)))
+                    .collect();
+                new_content.push_str(&format!(
+                    r#"<div id="{example_class_name}-{key}" class="{example_class_name}" data-lang="{lang}"><pre><code class="language-{lang}{classes}">"#
+                ));
+                new_content.push_str(&html_escape(&variant.body));
+                new_content.push_str(r#"</code></pre>"#);
+                new_content.push_str(&output_panel(variant));
+                new_content.push_str(r#"</div>"#);
+            }
+            CodeBlocks::Native => {
+                // Blank lines around the fence turn Markdown back on inside
+                // the <div>
+                let fence = "`".repeat(fence_len(&variant.body));
+                let info: String = std::iter::once(lang)
+                    .chain(&variant.attrs)
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(",");
+                new_content.push_str(&format!(
+                    r#"<div id="{example_class_name}-{key}" class="{example_class_name}" data-lang="{lang}">"#
+                ));
+                new_content.push_str(&format!("\n\n{fence}{info}\n"));
+                new_content.push_str(&variant.body);
+                new_content.push_str(&format!("{fence}\n\n"));
+                if variant.output.is_some() {
+                    new_content.push_str(&output_panel(variant));
+                    new_content.push_str("\n\n");
+                }
+                new_content.push_str("</div>\n");
+            }
+        }
+    }
+
+    // With sticky languages, the reader's last pick wins over the default.
+    // A script registered as an asset only loads at the end of the page.
+    let init = if ctx.config.sticky {
+        "restoreCodeExample"
+    } else {
+        "changeCodeExample"
+    };
+    new_content.push_str(&format!(
+        r#"<script>(()=>{{const show=()=>{init}("{example_class_name}", "{example_class_name}-{first_key}");if(typeof {init}==="function"){{show()}}else{{document.addEventListener("DOMContentLoaded",show)}}}})()</script>"#
+    ));
+
+    // Blank line, finally
+    new_content.push_str("\n\n");
+}
+
+/// A collapsed panel with what the variant's code printed, if it was run.
+fn output_panel(variant: &Variant) -> String {
+    match &variant.output {
+        Some(output) => format!(
+            r#"<details class="code-example-output"><summary>Output</summary><pre><code class="language-text">{}</code></pre></details>"#,
+            html_escape(output)
+        ),
+        None => String::new(),
+    }
+}
+
+/// Backticks needed to fence `body` in Markdown: more than any fence-like run
+/// of backticks starting one of its lines.
+fn fence_len(body: &str) -> usize {
+    body.lines()
+        .map(|line| {
+            let line = line.trim_start_matches(' ');
+            line.len() - line.trim_start_matches('`').len()
+        })
+        .filter(|run| *run >= 3)
+        .max()
+        .map_or(3, |longest| longest + 1)
+}
+
+fn html_escape(text_to_escape: impl AsRef<str>) -> String {
+    let mut text = text_to_escape.as_ref().to_string();
+    text = text.replace('&', "&amp;");
+    text = text.replace('<', "&lt;");
+    text = text.replace('>', "&gt;");
+    text = text.replace('"', "&quot;");
+    text = text.replace('\'', "&#39;");
+    // Empty lines are cursed on markdown+HTML, so cursed I had to do this
+    // The empty emphasis text should render as nothing, but avoid leaving a
+    // blank line on the markdown book
+    text = text.replace("\n\n", "\n<em></em>\n");
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    const SOURCE: &str = "```multicode\n>>>>> rust\nfn id<X>(x: X) -> X {\n    x\n}\n<<<<<\n>>>>> cpp \"C++\"\nX id(X x) { return x; }\n<<<<<\n```\n";
+
+    fn render(renderer: &dyn MulticodeRenderer, config: &MulticodeConfig) -> String {
+        let block = &parse(SOURCE)[0];
+        renderer.render(block, &RenderContext { index: 0, config })
+    }
+
+    #[test]
+    fn select() {
+        let output = render(&SelectRenderer, &MulticodeConfig::default());
+        assert_eq!(
+            output,
+            r##"<div><select onchange="changeCodeExample('code-example-tab-0', event.target.value)" value="rust" class="code-example" data-group="code-example-tab-0" autocomplete="off"><option value="code-example-tab-0-rust" selected>rust</option><option value="code-example-tab-0-cpp">C++</option></select></div>
+<div id="code-example-tab-0-rust" class="code-example-tab-0" data-lang="rust"><pre><code class="language-rust">fn id&lt;X&gt;(x: X) -&gt; X {
+    x
+}
+</code></pre></div><div id="code-example-tab-0-cpp" class="code-example-tab-0" data-lang="cpp"><pre><code class="language-cpp">X id(X x) { return x; }
+</code></pre></div><script>(()=>{const show=()=>changeCodeExample("code-example-tab-0", "code-example-tab-0-rust");if(typeof changeCodeExample==="function"){show()}else{document.addEventListener("DOMContentLoaded",show)}})()</script>
+
+"##
+        );
+    }
+
+    #[test]
+    fn tabs() {
+        let output = render(&TabsRenderer, &MulticodeConfig::default());
+        assert_eq!(
+            output,
+            r##"<div class="code-example-tabs" role="tablist"><button type="button" role="tab" class="code-example-tab" data-group="code-example-tab-0" data-target="code-example-tab-0-rust" onclick="changeCodeExample('code-example-tab-0', 'code-example-tab-0-rust')">rust</button><button type="button" role="tab" class="code-example-tab" data-group="code-example-tab-0" data-target="code-example-tab-0-cpp" onclick="changeCodeExample('code-example-tab-0', 'code-example-tab-0-cpp')">C++</button></div>
+<div id="code-example-tab-0-rust" class="code-example-tab-0" data-lang="rust"><pre><code class="language-rust">fn id&lt;X&gt;(x: X) -&gt; X {
+    x
+}
+</code></pre></div><div id="code-example-tab-0-cpp" class="code-example-tab-0" data-lang="cpp"><pre><code class="language-cpp">X id(X x) { return x; }
+</code></pre></div><script>(()=>{const show=()=>changeCodeExample("code-example-tab-0", "code-example-tab-0-rust");if(typeof changeCodeExample==="function"){show()}else{document.addEventListener("DOMContentLoaded",show)}})()</script>
+
+"##
+        );
+    }
+
+    #[test]
+    fn native() {
+        let config = MulticodeConfig {
+            code_blocks: CodeBlocks::Native,
+            ..MulticodeConfig::default()
+        };
+        let output = render(&SelectRenderer, &config);
+        assert_eq!(
+            output,
+            r##"<div><select onchange="changeCodeExample('code-example-tab-0', event.target.value)" value="rust" class="code-example" data-group="code-example-tab-0" autocomplete="off"><option value="code-example-tab-0-rust" selected>rust</option><option value="code-example-tab-0-cpp">C++</option></select></div>
+<div id="code-example-tab-0-rust" class="code-example-tab-0" data-lang="rust">
+
+```rust
+fn id<X>(x: X) -> X {
+    x
+}
+```
+
+</div>
+<div id="code-example-tab-0-cpp" class="code-example-tab-0" data-lang="cpp">
+
+```cpp
+X id(X x) { return x; }
+```
+
+</div>
+<script>(()=>{const show=()=>changeCodeExample("code-example-tab-0", "code-example-tab-0-rust");if(typeof changeCodeExample==="function"){show()}else{document.addEventListener("DOMContentLoaded",show)}})()</script>
+
+"##
+        );
+    }
+
+    #[test]
+    fn attrs_are_escaped() {
+        let mut block = parse(SOURCE).remove(0);
+        block.variants[0].attrs.push("a\"b<c>".to_owned());
+        let output = SelectRenderer.render(
+            &block,
+            &RenderContext {
+                index: 0,
+                config: &MulticodeConfig::default(),
+            },
+        );
+        assert!(output.contains(r#"<code class="language-rust a&quot;b&lt;c&gt;">"#));
+    }
+}